@@ -0,0 +1,263 @@
+//! Minimal LMTP (RFC 2033) front end.
+//!
+//! Implements just enough of the state machine to relay a session's message
+//! to [`crate::deliver`]: `LHLO`, `MAIL FROM`, one or more `RCPT TO`, and
+//! `DATA`, plus `RSET`, `NOOP` and `QUIT`. Every other command is rejected.
+//! Unlike SMTP, LMTP replies to
+//! `DATA` with one status line per accepted recipient rather than a single
+//! aggregate reply, which is what lets a downstream MTA (Postfix, Exim)
+//! retry per-recipient instead of the whole envelope.
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::spill::SpillBuf;
+use crate::{deliver, Address, MainError};
+
+/// Longest line (command or `DATA` line) a session will buffer before
+/// rejecting it. `BufReader::lines()` has no such cap and will happily grow
+/// its internal `String` without bound for an unterminated line, which a
+/// single misbehaving or compromised upstream MTA could use to balloon
+/// memory on a connection handled inline in this (long-running,
+/// many-connections-at-once) process — defeating the point of spilling
+/// large messages to a memfd elsewhere.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Runs a single LMTP session to completion over `stream`, logging but not
+/// propagating per-connection errors so one bad client can't bring down the
+/// listener.
+pub(crate) async fn run_session<S>(stream: S, local_hostname: &str)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    if let Err(err) = session(stream, local_hostname).await {
+        log::error!("lmtp session ended with error: {:?}", err);
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    mail_from: Option<String>,
+    rcpts: Vec<Address>,
+}
+
+async fn session<S>(stream: S, local_hostname: &str) -> Result<(), MainError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (read_half, mut write_half) = tokio::io::split(stream);
+    let mut reader = BufReader::new(read_half);
+
+    write_half
+        .write_all(format!("220 {} LMTP server ready\r\n", local_hostname).as_bytes())
+        .await?;
+
+    let mut envelope = Envelope::default();
+
+    loop {
+        let line = match read_line_capped(&mut reader, MAX_LINE_LEN).await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(MainError::LineTooLong(_)) => {
+                write_half.write_all(b"500 5.5.2 line too long\r\n").await?;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        let line = line.trim_end();
+        let upper = line.to_ascii_uppercase();
+
+        if let Some(hostname) = upper.strip_prefix("LHLO") {
+            let _ = hostname;
+            write_half
+                .write_all(format!("250-{}\r\n250 PIPELINING\r\n", local_hostname).as_bytes())
+                .await?;
+        } else if let Some(rest) = strip_command(&upper, line, "MAIL FROM:") {
+            envelope = Envelope::default();
+            envelope.mail_from = Some(extract_path(rest).to_string());
+            write_half.write_all(b"250 2.1.0 Ok\r\n").await?;
+        } else if let Some(rest) = strip_command(&upper, line, "RCPT TO:") {
+            if envelope.mail_from.is_none() {
+                write_half.write_all(b"503 5.5.1 MAIL FROM first\r\n").await?;
+                continue;
+            }
+            let email = extract_path(rest).to_string();
+            envelope.rcpts.push(Address { name: None, email });
+            write_half.write_all(b"250 2.1.5 Ok\r\n").await?;
+        } else if upper == "DATA" {
+            if envelope.rcpts.is_empty() {
+                write_half.write_all(b"503 5.5.1 RCPT TO first\r\n").await?;
+                continue;
+            }
+            write_half
+                .write_all(b"354 Start mail input; end with <CRLF>.<CRLF>\r\n")
+                .await?;
+            let raw = match read_data(&mut reader).await {
+                Ok(raw) => raw,
+                Err(MainError::LineTooLong(_)) => {
+                    write_half.write_all(b"552 5.3.4 line too long\r\n").await?;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+
+            match deliver(raw, envelope.rcpts.clone(), false).await {
+                Ok(()) => {
+                    for rcpt in &envelope.rcpts {
+                        write_half
+                            .write_all(format!("250 2.1.5 <{}> delivered\r\n", rcpt.email).as_bytes())
+                            .await?;
+                    }
+                }
+                Err(err) => {
+                    log::error!("lmtp delivery failed: {:?}", err);
+                    let (code, enhanced) = reply_code(&err);
+                    for rcpt in &envelope.rcpts {
+                        write_half
+                            .write_all(
+                                format!("{} {} <{}> delivery failed\r\n", code, enhanced, rcpt.email)
+                                    .as_bytes(),
+                            )
+                            .await?;
+                    }
+                }
+            }
+            envelope = Envelope::default();
+        } else if upper == "QUIT" {
+            write_half.write_all(b"221 2.0.0 Bye\r\n").await?;
+            break;
+        } else if upper == "RSET" {
+            envelope = Envelope::default();
+            write_half.write_all(b"250 2.0.0 Ok\r\n").await?;
+        } else if upper == "NOOP" {
+            write_half.write_all(b"250 2.0.0 Ok\r\n").await?;
+        } else {
+            write_half
+                .write_all(b"500 5.5.2 Command not recognized\r\n")
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads one line (without its trailing `\r\n`/`\n`) from `reader`, capped
+/// at `max_len` bytes. Returns `Ok(None)` at EOF. Unlike
+/// `AsyncBufReadExt::lines()`, a line that grows past `max_len` bytes
+/// fails fast with `MainError::LineTooLong` rather than
+/// continuing to grow the buffer — the caller is expected to close the
+/// connection rather than try to resynchronize on one.
+async fn read_line_capped<R>(reader: &mut R, max_len: usize) -> Result<Option<String>, MainError>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return if buf.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(String::from_utf8(buf)?))
+            };
+        }
+        match available.iter().position(|&b| b == b'\n') {
+            Some(pos) => {
+                buf.extend_from_slice(&available[..pos]);
+                reader.consume(pos + 1);
+                if buf.len() > max_len {
+                    return Err(MainError::LineTooLong("line exceeded maximum length"));
+                }
+                if buf.last() == Some(&b'\r') {
+                    buf.pop();
+                }
+                return Ok(Some(String::from_utf8(buf)?));
+            }
+            None => {
+                let read = available.len();
+                buf.extend_from_slice(available);
+                reader.consume(read);
+                if buf.len() > max_len {
+                    return Err(MainError::LineTooLong("line exceeded maximum length"));
+                }
+            }
+        }
+    }
+}
+
+/// Reads the `DATA` section up to the terminating `.` line, unescaping
+/// dot-stuffed lines, and returns the raw message bytes. Spills to a memfd
+/// via [`SpillWriter`] rather than growing one big `Vec` for large
+/// messages.
+async fn read_data<S>(
+    reader: &mut BufReader<tokio::io::ReadHalf<S>>,
+) -> Result<SpillBuf, MainError>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    let mut writer = crate::spill::SpillWriter::new();
+    while let Some(line) = read_line_capped(reader, MAX_LINE_LEN).await? {
+        if line == "." {
+            break;
+        }
+        let line = line.strip_prefix('.').unwrap_or(&line);
+        writer.write(line.as_bytes())?;
+        writer.write(b"\r\n")?;
+    }
+    writer.finish()
+}
+
+/// Case-insensitively matches `prefix` against `upper` and, if present,
+/// returns the corresponding slice of the original (non-uppercased) `line`.
+fn strip_command<'a>(upper: &str, line: &'a str, prefix: &str) -> Option<&'a str> {
+    if upper.starts_with(prefix) {
+        Some(line[prefix.len()..].trim())
+    } else {
+        None
+    }
+}
+
+/// Pulls the reverse-path/forward-path out of a `MAIL FROM:`/`RCPT TO:`
+/// argument, ignoring any trailing ESMTP parameters (`NOTIFY=...`,
+/// `SIZE=...`) a DSN-aware MTA may append after the closing `>`. Falls back
+/// to the first whitespace-delimited token for the (non-conformant) case
+/// where the address isn't wrapped in angle brackets at all.
+fn extract_path(rest: &str) -> &str {
+    let rest = rest.trim();
+    if let Some(start) = rest.find('<') {
+        if let Some(end) = rest[start + 1..].find('>') {
+            return &rest[start + 1..start + 1 + end];
+        }
+    }
+    rest.split_whitespace().next().unwrap_or(rest)
+}
+
+/// Maps a `deliver()` failure to an LMTP reply code: permanent failures
+/// (malformed message, missing DKIM key, bad sender syntax) get a `5xx` so
+/// a downstream MTA (Postfix, Exim) stops retrying, while transient ones
+/// (network/API hiccups) get a `4xx` so it tries again later. When the
+/// MailChannels API itself is what failed, its own status code decides
+/// which side of that line we land on.
+fn reply_code(err: &MainError) -> (u16, &'static str) {
+    match err {
+        MainError::API(status, _) if (500..600).contains(status) => (451, "4.3.0"),
+        MainError::API(status, _) if (400..500).contains(status) => (550, "5.6.0"),
+        MainError::API(_, _) => (451, "4.3.0"),
+        MainError::Reqwest(_) | MainError::Io(_) | MainError::TaskJoin(_) => (451, "4.3.0"),
+        MainError::NoHeaders(_)
+        | MainError::HeaderValue(_)
+        | MainError::CouldntSerialize(_)
+        | MainError::InvalidFrom(_)
+        | MainError::AttachmentIssue(_)
+        | MainError::InvalidUtf8(_)
+        | MainError::NoSenderDomain(_, _)
+        | MainError::NoDkimForDomain(_, _)
+        | MainError::DkimKeyDecodeFailed(_, _)
+        | MainError::TooManyHeaders(_)
+        | MainError::MissingHeader(_)
+        | MainError::Usage(_)
+        | MainError::EmptyAddressGroup(_)
+        | MainError::TemplateDataIssue(_)
+        | MainError::MimeNestingTooDeep(_)
+        | MainError::LineTooLong(_) => (550, "5.6.0"),
+    }
+}
@@ -1,14 +1,23 @@
 use std::collections::HashMap;
 use std::default::Default;
+use std::fmt;
 use std::io::Read;
 
+use base64::Engine;
+
 use mail_parser::{HeaderName, HeaderValue, MimeHeaders};
 
 use quick_error::quick_error;
 
-use serde_with::base64::Base64;
+use serde::ser::SerializeMap;
+use serde::Serialize;
 use serde_with::{serde_as, skip_serializing_none};
 
+mod lmtp;
+mod spill;
+
+use spill::SpillBuf;
+
 const fn api_key() -> &'static str {
     env!("MDA_MAILCHANNELS_API_KEY")
 }
@@ -59,16 +68,69 @@ struct MailChannelsBody {
     transactional: Option<bool>,
 }
 
-#[serde_as]
-#[derive(serde::Serialize)]
+/// An attachment's content lives in a [`SpillBuf`] — a memfd for anything
+/// past the spill threshold (1 MiB by default) — rather than a `Vec<u8>`, and its `Serialize` impl
+/// (below) base64-encodes it a chunk at a time straight into the output
+/// writer, so a large attachment is never held again in full as a base64
+/// `String` the way a derived `#[serde_as(as = "Base64")]` field would.
 struct Attachment {
-    #[serde_as(as = "Base64")]
-    content: Vec<u8>,
+    content: SpillBuf,
+    /// Set when the part carried a `Content-ID`, so a `cid:` reference in an
+    /// HTML body can resolve it as an inline resource (multipart/related).
+    content_id: Option<String>,
     filename: String,
-    #[serde(rename = "type")]
     mimetype: String,
 }
 
+impl Serialize for Attachment {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let view = self
+            .content
+            .view()
+            .map_err(|_| serde::ser::Error::custom("failed to view spilled attachment content"))?;
+
+        let mut map = serializer.serialize_map(Some(if self.content_id.is_some() { 4 } else { 3 }))?;
+        map.serialize_entry("content", &Base64Display(&view))?;
+        if let Some(content_id) = &self.content_id {
+            map.serialize_entry("content_id", content_id)?;
+        }
+        map.serialize_entry("filename", &self.filename)?;
+        map.serialize_entry("type", &self.mimetype)?;
+        map.end()
+    }
+}
+
+/// `Display`s the wrapped bytes as base64, one bounded-size chunk at a time,
+/// so `serializer.collect_str` (which formats a `Display` straight into the
+/// output writer) never has to build the whole encoded string in memory —
+/// the point of spilling the raw bytes to a memfd in the first place would
+/// otherwise be undone by base64-encoding them into one giant `String`.
+struct Base64Display<'a>(&'a [u8]);
+
+impl fmt::Display for Base64Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A multiple of 3 so no chunk boundary ever lands mid-group and
+        // needs '=' padding.
+        const CHUNK_LEN: usize = 48 * 1024;
+        for chunk in self.0.chunks(CHUNK_LEN) {
+            f.write_str(&base64::engine::general_purpose::STANDARD.encode(chunk))?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Base64Display<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 #[derive(serde::Serialize)]
 struct Content {
     template_type: Option<String>,
@@ -91,7 +153,7 @@ struct Personalization {
     cc: Option<Vec<Address>>,
     #[serde(flatten)]
     dkim: Option<DkimInfo>,
-    dynamic_template_data: Option<HashMap<String, ()>>, //TemplateValue>>,
+    dynamic_template_data: Option<HashMap<String, TemplateValue>>,
     from: Option<Address>,
     headers: Option<HashMap<String, String>>,
     reply_to: Option<Address>,
@@ -99,14 +161,55 @@ struct Personalization {
     to: Vec<Address>,
 }
 
-// #[derive(serde::Serialize)]
-// enum TemplateValue {
-//     String(String),
-//     Boolean(bool),
-//     Number(f64),
-//     List(Vec<TemplateValue>),
-//     Map(HashMap<String, TemplateValue>),
-// }
+/// A value substituted into a MailChannels mustache template via
+/// `dynamic_template_data`. Serializes as plain JSON (a bare string,
+/// number, etc.) rather than as a serde-tagged enum, since that's the
+/// shape MailChannels' mustache renderer expects. `Null` round-trips as
+/// JSON `null` rather than being coerced into some other falsy value —
+/// operator-supplied template data should reach the template unchanged.
+enum TemplateValue {
+    Null,
+    String(String),
+    Boolean(bool),
+    Number(f64),
+    List(Vec<TemplateValue>),
+    Map(HashMap<String, TemplateValue>),
+}
+
+impl serde::Serialize for TemplateValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            TemplateValue::Null => serializer.serialize_none(),
+            TemplateValue::String(s) => serializer.serialize_str(s),
+            TemplateValue::Boolean(b) => serializer.serialize_bool(*b),
+            TemplateValue::Number(n) => serializer.serialize_f64(*n),
+            TemplateValue::List(list) => list.serialize(serializer),
+            TemplateValue::Map(map) => map.serialize(serializer),
+        }
+    }
+}
+
+impl From<serde_json::Value> for TemplateValue {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => TemplateValue::Null,
+            serde_json::Value::Bool(b) => TemplateValue::Boolean(b),
+            serde_json::Value::Number(n) => TemplateValue::Number(n.as_f64().unwrap_or_default()),
+            serde_json::Value::String(s) => TemplateValue::String(s),
+            serde_json::Value::Array(items) => {
+                TemplateValue::List(items.into_iter().map(TemplateValue::from).collect())
+            }
+            serde_json::Value::Object(map) => TemplateValue::Map(
+                map.into_iter()
+                    .map(|(key, value)| (key, TemplateValue::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
 
 #[derive(serde::Serialize)]
 struct TrackingSettings {
@@ -142,9 +245,80 @@ quick_error! {
         TooManyHeaders(err: &'static str)
         MissingHeader(err: &'static str)
         API(err: u16, text: String)
+        Usage(err: &'static str)
+        EmptyAddressGroup(err: &'static str)
+        TemplateDataIssue(err: &'static str)
+        MimeNestingTooDeep(err: &'static str)
+        LineTooLong(err: &'static str)
+        TaskJoin(err: tokio::task::JoinError) { from() }
     }
 }
 
+/// Header carrying base64url-encoded JSON to populate
+/// `dynamic_template_data`, as an alternative to a sidecar file. Stripped
+/// out of `headers` before the rest are forwarded, since it's addressed to
+/// us rather than to MailChannels.
+const TEMPLATE_DATA_HEADER: &str = "X-MC-Template-Data";
+
+/// Env var naming a JSON file whose top-level object becomes
+/// `dynamic_template_data` when no `X-MC-Template-Data` header is present.
+/// Read once per message in the single-shot stdin MDA, where that means
+/// "this process's one message"; under `--lmtp` the process handles many
+/// messages across its lifetime, so [`build_body`] only consults it for
+/// `Mode::Stdin` deliveries, not per-message ones arriving over LMTP.
+const TEMPLATE_DATA_FILE_VAR: &str = "MDA_MAILCHANNELS_TEMPLATE_DATA_FILE";
+
+/// Reads `X-MC-Template-Data` off `msg`, if present, and decodes it into
+/// template substitution data.
+fn template_data_from_header(
+    msg: &mail_parser::Message,
+) -> Result<Option<HashMap<String, TemplateValue>>, MainError> {
+    let raw_value = msg
+        .headers_raw()
+        .find(|(name, _)| name.eq_ignore_ascii_case(TEMPLATE_DATA_HEADER))
+        .map(|(_, value)| value);
+    let Some(raw_value) = raw_value else {
+        return Ok(None);
+    };
+    let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(raw_value[1..].trim())
+        .map_err(|_| MainError::TemplateDataIssue("X-MC-Template-Data is not valid base64url"))?;
+    template_data_object(&decoded).map(Some)
+}
+
+/// Reads the JSON file named by `MDA_MAILCHANNELS_TEMPLATE_DATA_FILE`, if
+/// set, and decodes it into template substitution data. Only meaningful for
+/// a single-shot stdin delivery — see [`TEMPLATE_DATA_FILE_VAR`] — so
+/// callers must not reach for this under `--lmtp`.
+fn template_data_from_sidecar() -> Result<Option<HashMap<String, TemplateValue>>, MainError> {
+    let path = match std::env::var_os(TEMPLATE_DATA_FILE_VAR) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let contents = std::fs::read(path)?;
+    template_data_object(&contents).map(Some)
+}
+
+/// Parses `bytes` as a JSON object and converts it into
+/// `dynamic_template_data`'s map of [`TemplateValue`]s.
+fn template_data_object(bytes: &[u8]) -> Result<HashMap<String, TemplateValue>, MainError> {
+    match serde_json::from_slice(bytes)? {
+        serde_json::Value::Object(map) => Ok(map
+            .into_iter()
+            .map(|(key, value)| (key, TemplateValue::from(value)))
+            .collect()),
+        _ => Err(MainError::TemplateDataIssue(
+            "template data must decode to a JSON object",
+        )),
+    }
+}
+
+/// Deepest `multipart/*` nesting [`walk_mime_tree`] will follow before
+/// giving up. Legitimate mail clients don't nest more than a handful of
+/// levels deep; this is generous headroom over that with a hard stop well
+/// short of blowing the stack.
+const MAX_MIME_DEPTH: usize = 64;
+
 /// picks out content type and subtype into regular mimetype string
 fn stringify_content_type(ct: &mail_parser::ContentType) -> String {
     let ctype = ct.ctype();
@@ -165,29 +339,185 @@ fn stringify_content_type(ct: &mail_parser::ContentType) -> String {
     content_type
 }
 
-fn flatten_addresses(v: &Vec<HeaderValue>) -> Vec<Address> {
-    v.iter()
-        .flat_map(|headerval| {
-            headerval
-                .clone()
-                .into_address()
-                .expect("header value was not an address D:") // TODO: expect nothing!!
+/// Recursively walks the MIME tree rooted at `parts[idx]`, the way an IMAP
+/// server walks a message to produce a BODYSTRUCTURE, sorting each part
+/// into `content` (textual bodies) or `attachments` (everything else,
+/// including `multipart/related` inline resources) rather than flattening
+/// the whole message into `text/plain`.
+///
+/// `multipart/alternative` children are collected separately and reordered
+/// so `text/plain` precedes `text/html` — MailChannels renders `content` in
+/// array order and treats the last HTML entry as the primary body.
+///
+/// `depth` bounds the recursion: a crafted message can nest `multipart/*`
+/// parts deeply enough to blow the stack, which would abort the whole
+/// process rather than just fail this one message — fatal when `main.rs`
+/// is handling many concurrent LMTP sessions at once. Callers start at 0.
+fn walk_mime_tree(
+    msg: &mail_parser::Message,
+    idx: usize,
+    content: &mut Vec<Content>,
+    attachments: &mut Vec<Attachment>,
+    depth: usize,
+) -> Result<(), MainError> {
+    if depth > MAX_MIME_DEPTH {
+        return Err(MainError::MimeNestingTooDeep(
+            "message MIME tree is nested too deeply",
+        ));
+    }
+    let part = msg
+        .parts
+        .get(idx)
+        .ok_or(MainError::AttachmentIssue("mime part index out of range"))?;
+    let content_type = part.content_type();
+
+    if content_type
+        .map(|ct| ct.ctype().eq_ignore_ascii_case("multipart"))
+        .unwrap_or(false)
+    {
+        let children = match &part.body {
+            mail_parser::PartType::Multipart(children) => children,
+            _ => return Ok(()),
+        };
+        let is_alternative = content_type
+            .and_then(|ct| ct.subtype())
+            .map(|subtype| subtype.eq_ignore_ascii_case("alternative"))
+            .unwrap_or(false);
+        if is_alternative {
+            let mut alternatives = Vec::new();
+            for &child in children {
+                walk_mime_tree(msg, child, &mut alternatives, attachments, depth + 1)?;
+            }
+            alternatives.sort_by_key(|c| (c.content_type == "text/html") as u8);
+            content.extend(alternatives);
+        } else {
+            for &child in children {
+                walk_mime_tree(msg, child, content, attachments, depth + 1)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let is_textual = content_type
+        .map(|ct| ct.ctype().eq_ignore_ascii_case("text"))
+        .unwrap_or(false)
+        && !part
+            .content_disposition()
+            .map(|cd| cd.is_attachment())
+            .unwrap_or(false);
+
+    if is_textual {
+        content.push(Content {
+            template_type: None,
+            value: String::from_utf8(part.contents().to_vec())?,
+            content_type: content_type.map(stringify_content_type).ok_or(
+                MainError::AttachmentIssue("text part missing content type"),
+            )?,
+        });
+    } else {
+        attachments.push(Attachment {
+            filename: attachment_filename(part)
+                .ok_or(MainError::AttachmentIssue("attachment is missing filename"))?,
+            mimetype: content_type
+                .map(stringify_content_type)
+                .unwrap_or_else(|| String::from("application/octet-stream")),
+            content: SpillBuf::capture(std::io::Cursor::new(part.contents()))?,
+            content_id: part.content_id().map(String::from),
+        });
+    }
+    Ok(())
+}
+
+/// Derives an attachment's filename from `Content-Disposition: filename`,
+/// falling back to the `Content-Type` `name` parameter.
+fn attachment_filename(part: &mail_parser::MessagePart) -> Option<String> {
+    part.attachment_name()
+        .map(String::from)
+        .or_else(|| part.content_type()?.attribute("name").map(String::from))
+}
+
+/// Flattens a list of address headers into individual deliverable
+/// addresses, the way an IMAP envelope builder walks `To`/`Cc`/`Bcc`: a
+/// plain address list is taken as-is, and an RFC 5322 group (`Team: a@x,
+/// b@y;`) is descended into so every member is delivered to. The group's
+/// own display name is dropped; only its members become recipients.
+fn flatten_addresses(v: &Vec<HeaderValue>) -> Result<Vec<Address>, MainError> {
+    let mut addresses = Vec::new();
+    for headerval in v {
+        match headerval
+            .clone()
+            .into_address()
+            .expect("header value was not an address D:") // TODO: expect nothing!!
+        {
+            mail_parser::Address::List(list) => {
+                addresses.extend(list.iter().map(addr_to_address));
+            }
+            mail_parser::Address::Group(groups) => {
+                for group in groups {
+                    if group.addresses.is_empty() {
+                        return Err(MainError::EmptyAddressGroup(
+                            "address group yielded zero deliverable addresses",
+                        ));
+                    }
+                    addresses.extend(group.addresses.iter().map(addr_to_address));
+                }
+            }
+        }
+    }
+    Ok(addresses)
+}
+
+fn addr_to_address(addr: &mail_parser::Addr) -> Address {
+    Address {
+        name: addr.name.clone().map(|name| name.into_owned()),
+        // TODO: expect nothing!!:
+        email: addr
+            .address
+            .clone()
+            .map(|email| email.into_owned())
+            .expect("email was null?!? D:"),
+    }
+}
+
+/// Drops any address from `addresses` whose email already appears in one
+/// of the `known` lists, so the same recipient is never listed (and
+/// delivered) under two different personalization fields at once.
+fn exclude_known(addresses: Vec<Address>, known: &[&[Address]]) -> Vec<Address> {
+    addresses
+        .into_iter()
+        .filter(|addr| {
+            !known
                 .iter()
-                .cloned()
-                .collect::<Vec<mail_parser::Addr>>()
-        })
-        .map(|addr: mail_parser::Addr| Address {
-            name: addr.name.clone().map(|name| name.into_owned()),
-            // TODO: expect nothing!!:
-            email: addr
-                .address
-                .clone()
-                .map(|email| email.into_owned())
-                .expect("email was null?!? D:"),
+                .any(|list| list.iter().any(|seen| seen.email == addr.email))
         })
         .collect()
 }
 
+/// Which mode the binary was invoked in: a single-shot MDA reading one
+/// message from stdin (the historical behaviour), or a long-running LMTP
+/// (RFC 2033) front end listening on `addr`.
+enum Mode {
+    Stdin,
+    Lmtp(String),
+}
+
+/// Parses `--lmtp <addr>` out of the process arguments, falling back to the
+/// stdin MDA mode when it isn't present. `addr` starting with `/` is bound
+/// as a Unix socket path; anything else is treated as a TCP `host:port`.
+fn parse_args() -> Result<Mode, MainError> {
+    let mut args = std::env::args().skip(1);
+    match args.next() {
+        None => Ok(Mode::Stdin),
+        Some(flag) if flag == "--lmtp" => {
+            let addr = args
+                .next()
+                .ok_or(MainError::Usage("--lmtp requires an address argument"))?;
+            Ok(Mode::Lmtp(addr))
+        }
+        Some(_) => Err(MainError::Usage("usage: mda-mailchannels [--lmtp <addr>]")),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), MainError> {
     simplelog::TermLogger::init(
@@ -200,13 +530,140 @@ async fn main() -> Result<(), MainError> {
         simplelog::ColorChoice::Never,
     )
     .expect("couldn't init sinplelog::TermLogger");
-    let mut buf = Vec::with_capacity(8192);
+
+    match parse_args()? {
+        Mode::Stdin => run_stdin().await,
+        Mode::Lmtp(addr) => run_lmtp(&addr).await,
+    }
+}
+
+/// Binds `addr` (a Unix socket path if it starts with `/`, else a TCP
+/// `host:port`) and hands each accepted connection to [`lmtp::run_session`]
+/// on its own task, relaying every delivered message through [`deliver`].
+async fn run_lmtp(addr: &str) -> Result<(), MainError> {
+    let local_hostname =
+        std::env::var("HOSTNAME").unwrap_or_else(|_| String::from("localhost"));
+
+    if let Some(path) = addr.strip_prefix('/').map(|_| addr) {
+        let _ = std::fs::remove_file(path);
+        let listener = tokio::net::UnixListener::bind(path)?;
+        log::info!("lmtp server listening on unix socket {}", path);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let local_hostname = local_hostname.clone();
+            tokio::spawn(async move {
+                lmtp::run_session(stream, &local_hostname).await;
+            });
+        }
+    } else {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        log::info!("lmtp server listening on {}", addr);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let local_hostname = local_hostname.clone();
+            tokio::spawn(async move {
+                lmtp::run_session(stream, &local_hostname).await;
+            });
+        }
+    }
+}
+
+/// The original single-shot behaviour: read one message from stdin, derive
+/// its recipients from the `To` header (the envelope `deliver()` expects),
+/// and relay it. `Cc`/`Bcc` are not folded in here — `deliver()` derives
+/// those from the headers itself and personalizes them separately, so a
+/// `Bcc` recipient never ends up visible in the rendered `To:`.
+async fn run_stdin() -> Result<(), MainError> {
     let stdin_handle = std::io::stdin();
-    let mut stdin = stdin_handle.lock();
-    stdin.read_to_end(&mut buf)?;
+    let buf = SpillBuf::capture(stdin_handle.lock())?;
+    let view = buf.view()?;
+
+    let parser = mail_parser::MessageParser::default();
+    let msg = parser
+        .parse(&view)
+        .ok_or(MainError::NoHeaders("message has no headers"))?;
+
+    let to_values = header_values(&msg, &HeaderName::To);
+    if to_values.is_empty() {
+        return Err(MainError::MissingHeader("No recipient!!"));
+    }
+    let rcpts = flatten_addresses(&to_values)?;
+    drop(view);
+
+    deliver(buf, rcpts, true).await
+}
+
+/// Collects every occurrence of header `name` in `msg`, since a header like
+/// `To` may legally appear more than once.
+fn header_values(msg: &mail_parser::Message, name: &HeaderName) -> Vec<HeaderValue> {
+    msg.headers()
+        .iter()
+        .filter(|header| &header.name == name)
+        .map(|header| header.value.clone())
+        .collect()
+}
+
+/// Hands `body`'s JSON serialization to a blocking task writing into one
+/// side of a pipe, streams the other side as the POST's body, and awaits
+/// both the request and the blocking task together. Combined with each
+/// attachment's content already living in a [`SpillBuf`] and its
+/// `Serialize` impl base64-encoding it in bounded chunks (see
+/// `Attachment`/`Base64Display`), this keeps peak memory roughly constant
+/// in attachment size instead of scaling with it: the fully-serialized
+/// request never exists as one in-memory `String`, and neither does any
+/// attachment's base64 encoding.
+///
+/// If the blocking task fails partway (a bad attachment mmap, a serde
+/// error), dropping its end of the duplex pipe just looks like a
+/// truncated body to `send()` — joining the two lets us notice the real
+/// failure and return it, instead of only logging it while the caller
+/// sees whatever opaque status MailChannels returns for malformed JSON.
+async fn send_request(
+    client: &reqwest::Client,
+    headers: reqwest::header::HeaderMap,
+    body: MailChannelsBody,
+) -> Result<reqwest::Response, MainError> {
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let serialize = tokio::task::spawn_blocking(move || {
+        let sync_writer = tokio_util::io::SyncIoBridge::new(writer);
+        serde_json::to_writer(sync_writer, &body)
+    });
+    let request_body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(reader));
+    let send = client
+        .post("https://api.mailchannels.net/tx/v1/send")
+        .headers(headers)
+        .body(request_body)
+        .send();
+
+    let (serialize_result, send_result) = tokio::join!(serialize, send);
+    serialize_result??;
+    Ok(send_result?)
+}
+
+/// Parses `raw` as a MIME message and builds the MailChannels API body:
+/// mmap-ing `raw` (when it has spilled to a memfd), reading the sender's
+/// DKIM key off disk, and walking the MIME tree (which copies each
+/// attachment into its own `SpillBuf`) are all synchronous, potentially-blocking
+/// work, so [`deliver`] runs this on a blocking task rather than the async
+/// worker thread handling every other concurrent LMTP session.
+///
+/// `allow_sidecar_template_data` gates [`template_data_from_sidecar`]: the
+/// stdin MDA handles exactly one message per process invocation, so a
+/// process-wide env var naming a template data file is unambiguous, but
+/// under `--lmtp` one long-running process handles many unrelated
+/// messages, and honouring it there would silently stamp every message
+/// lacking its own `X-MC-Template-Data` header with whatever file was
+/// named at startup. The LMTP front end passes `false` (through
+/// `deliver`); the stdin MDA passes `true`.
+fn build_body(
+    raw: &SpillBuf,
+    rcpts: &[Address],
+    allow_sidecar_template_data: bool,
+) -> Result<MailChannelsBody, MainError> {
+    let view = raw.view()?;
     let parser = mail_parser::MessageParser::default();
     let msg = parser
-        .parse(&buf)
+        .parse(&view)
         .ok_or(MainError::NoHeaders("message has no headers"))?;
 
     #[cfg(debug_assertions)]
@@ -329,9 +786,16 @@ async fn main() -> Result<(), MainError> {
     let allowed_headers: HashMap<_, _> = msg
         .headers_raw()
         .filter(|header| !headers::FORBIDDEN.contains(&header.0.into()))
+        .filter(|header| !header.0.eq_ignore_ascii_case(TEMPLATE_DATA_HEADER))
         .map(|header| (String::from(header.0), String::from(&header.1[1..])))
         .collect();
 
+    let template_data = match template_data_from_header(&msg)? {
+        Some(data) => Some(data),
+        None if allow_sidecar_template_data => template_data_from_sidecar()?,
+        None => None,
+    };
+
     let subject = forbidden_headers
         .get(&HeaderName::Subject)
         .ok_or(MainError::MissingHeader("need a Subject!"))?;
@@ -345,40 +809,37 @@ async fn main() -> Result<(), MainError> {
         Some(text) => text.into_owned(),
     };
 
+    let mut content = Vec::new();
+    let mut attachments = Vec::new();
+    walk_mime_tree(&msg, 0, &mut content, &mut attachments, 0)?;
+    if template_data.is_some() {
+        for entry in content.iter_mut() {
+            entry.template_type = Some(String::from("mustache"));
+        }
+    }
+
+    // `rcpts` (the `to` list) may already come from an authoritative
+    // source distinct from the headers — the LMTP envelope's `RCPT TO`
+    // list, in particular. Drop anyone from `cc`/`bcc` who's already in
+    // `to`, and anyone from `bcc` already in `cc`, so the same recipient
+    // never gets listed — and delivered — twice.
+    let cc = forbidden_headers
+        .get(&HeaderName::Cc)
+        .map(flatten_addresses)
+        .transpose()?
+        .map(|addrs| exclude_known(addrs, &[rcpts]));
+    let bcc = forbidden_headers
+        .get(&HeaderName::Bcc)
+        .map(flatten_addresses)
+        .transpose()?
+        .map(|addrs| exclude_known(addrs, &[rcpts, cc.as_deref().unwrap_or(&[])]));
+
     let body = MailChannelsBody {
-        attachments: msg
-            .attachments()
-            .map(|attachment| match attachment.attachment_name() {
-                Some(filename) => Ok(Attachment {
-                    filename: filename.to_string(),
-                    mimetype: String::from("text/plain"),
-                    content: attachment.contents().to_vec(),
-                }),
-                None => Err(MainError::AttachmentIssue("attachment is missing filename")),
-            })
-            .collect::<Result<Vec<_>, _>>()
-            .map(Some)?,
-        content: msg
-            .html_bodies()
-            .map(|body| {
-                Ok(Content {
-                    template_type: None,
-                    value: String::from_utf8(body.contents().to_vec())?,
-                    content_type: body.content_type().map(stringify_content_type).ok_or(
-                        MainError::AttachmentIssue("presumed html body missing content type"),
-                    )?,
-                })
-            })
-            .chain(msg.text_bodies().map(|body| {
-                Ok(Content {
-                    template_type: None,
-                    value: String::from_utf8(body.contents().to_vec())?,
-                    content_type: body.content_type().map(stringify_content_type).ok_or(
-                        MainError::AttachmentIssue("presumed plain text body missing content type"),
-                    )?,
-                })
-            }))
-            .collect::<Result<Vec<Content>, MainError>>()?,
+        attachments: match attachments.len() {
+            0 => None,
+            _ => Some(attachments),
+        },
+        content,
         dkim,
         from,
         headers: match allowed_headers.len() {
@@ -386,26 +847,20 @@ async fn main() -> Result<(), MainError> {
             _ => Some(allowed_headers),
         },
         personalizations: vec![Personalization {
-            bcc: forbidden_headers
-                .get(&HeaderName::Bcc)
-                .map(flatten_addresses),
-            cc: forbidden_headers
-                .get(&HeaderName::Cc)
-                .map(flatten_addresses),
+            bcc,
+            cc,
             dkim: None,
-            dynamic_template_data: None,
+            dynamic_template_data: template_data,
             from: None,
             headers: None,
             reply_to: None,
             subject: None,
-            to: forbidden_headers
-                .get(&HeaderName::To)
-                .map(flatten_addresses)
-                .ok_or(MainError::MissingHeader("No recipient!!"))?,
+            to: rcpts.to_vec(),
         }],
         reply_to: match forbidden_headers
             .get(&HeaderName::ReplyTo)
             .map(flatten_addresses)
+            .transpose()?
         {
             Some(v) if v.len() > 1 => Err(MainError::TooManyHeaders(
                 "should only have one Reply-To address!",
@@ -417,9 +872,26 @@ async fn main() -> Result<(), MainError> {
         tracking_settings: None,
         transactional: None,
     };
-    let body = serde_json::to_string(&body)?;
-    log::trace!("json body.content: {}", body);
-    //todo!("construct mailchannels response body");
+    Ok(body)
+}
+
+/// Parses `raw` as a MIME message, builds the MailChannels API body, and
+/// POSTs it to `/tx/v1/send`. `rcpts` is the authoritative recipient list
+/// for the delivery's personalization (the stdin MDA derives it from
+/// headers; the LMTP front end supplies the envelope `RCPT TO` list
+/// instead, since the two need not agree). Takes `raw`/`rcpts` by value so
+/// [`build_body`]'s synchronous work can run on a `spawn_blocking` task
+/// without borrowing across the `.await`. `allow_sidecar_template_data`
+/// is forwarded to [`build_body`] — see its doc comment.
+pub(crate) async fn deliver(
+    raw: SpillBuf,
+    rcpts: Vec<Address>,
+    allow_sidecar_template_data: bool,
+) -> Result<(), MainError> {
+    let body = tokio::task::spawn_blocking(move || {
+        build_body(&raw, &rcpts, allow_sidecar_template_data)
+    })
+    .await??;
 
     let client = reqwest::Client::new();
     let mut headers = reqwest::header::HeaderMap::new();
@@ -433,12 +905,7 @@ async fn main() -> Result<(), MainError> {
         .insert("X-Api-Key", api_key().parse()?)
         .inspect(|x| panic!("map already had X-Api-Key: {:?}", x));
 
-    let response = client
-        .post("https://api.mailchannels.net/tx/v1/send")
-        .headers(headers)
-        .body(body)
-        .send()
-        .await?;
+    let response = send_request(&client, headers, body).await?;
     if response.status() == reqwest::StatusCode::OK {
         log::info!("received sandbox 200 ok");
     } else if response.status() == reqwest::StatusCode::ACCEPTED {
@@ -0,0 +1,193 @@
+//! A byte buffer that spills to a sealed, read-only `memfd` once it grows
+//! past a threshold, instead of an ever-larger heap `Vec<u8>`.
+//!
+//! A message with large attachments used to be held in RAM several times
+//! over: once as the raw read buffer, again per base64-encoded attachment,
+//! and again as the fully-serialized JSON string. [`SpillBuf`] backs both
+//! the raw message (`run_stdin`/LMTP `DATA`) and each attachment's content
+//! (`walk_mime_tree` in `main.rs`); the base64 encoding and final JSON
+//! serialization are handled by streaming them straight into the outgoing
+//! request instead (see `Attachment`'s `Serialize` impl and
+//! `send_request` in `main.rs`). Together that keeps peak memory roughly
+//! constant in attachment size rather than scaling with it, which is what
+//! matters under a tight cgroup memory limit.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::os::fd::AsRawFd;
+
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+
+use crate::MainError;
+
+/// Buffers larger than this many bytes are spilled to a memfd rather than
+/// kept in the heap. Override with `MDA_MAILCHANNELS_SPILL_THRESHOLD`
+/// (bytes) for testing.
+fn threshold() -> usize {
+    std::env::var("MDA_MAILCHANNELS_SPILL_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1024 * 1024)
+}
+
+enum Backing {
+    Memory(Vec<u8>),
+    Spilled(std::fs::File),
+}
+
+pub(crate) struct SpillBuf {
+    backing: Backing,
+    len: usize,
+}
+
+impl SpillBuf {
+    /// Reads all of `reader` into a `SpillBuf`, spilling to a sealed memfd
+    /// as soon as the accumulated size crosses [`threshold`].
+    pub(crate) fn capture<R: Read>(mut reader: R) -> Result<Self, MainError> {
+        let mut writer = SpillWriter::new();
+        let mut chunk = [0u8; 8192];
+        loop {
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                return writer.finish();
+            }
+            writer.write(&chunk[..read])?;
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// A read-only view of the whole buffer: a borrow of the heap `Vec` for
+    /// small messages, or a read-only `mmap` of the sealed memfd for
+    /// spilled ones. `mail_parser` can parse directly against either
+    /// without a second copy of the message.
+    pub(crate) fn view(&self) -> Result<BufView<'_>, MainError> {
+        match &self.backing {
+            Backing::Memory(v) => Ok(BufView::Memory(v.as_slice())),
+            Backing::Spilled(file) => {
+                let len = NonZeroUsize::new(self.len)
+                    .ok_or(MainError::AttachmentIssue("spilled buffer is empty"))?;
+                // SAFETY: `file` is a sealed, immutable memfd for the
+                // lifetime of this `SpillBuf`, so the mapping stays valid
+                // and its contents never change out from under us.
+                let ptr = unsafe {
+                    mmap(
+                        None,
+                        len,
+                        ProtFlags::PROT_READ,
+                        MapFlags::MAP_PRIVATE,
+                        file.as_raw_fd(),
+                        0,
+                    )
+                }
+                .map_err(|_| MainError::AttachmentIssue("failed to mmap spilled buffer"))?;
+                Ok(BufView::Mapped {
+                    ptr: ptr.cast(),
+                    len: self.len,
+                })
+            }
+        }
+    }
+}
+
+/// Accumulates bytes fed to it incrementally (e.g. one LMTP `DATA` line at
+/// a time), transparently switching from an in-memory `Vec` to a memfd
+/// once [`threshold`] is crossed: the `Vec` is copied into the memfd once
+/// and then dropped. The memfd is sealed by [`SpillWriter::finish`].
+pub(crate) struct SpillWriter {
+    limit: usize,
+    state: WriterState,
+}
+
+enum WriterState {
+    Memory(Vec<u8>),
+    Spilled(std::fs::File, memfd::Memfd),
+}
+
+impl SpillWriter {
+    pub(crate) fn new() -> Self {
+        let limit = threshold();
+        SpillWriter {
+            limit,
+            state: WriterState::Memory(Vec::with_capacity(8192.min(limit))),
+        }
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> Result<(), MainError> {
+        match &mut self.state {
+            WriterState::Memory(mem) => {
+                mem.extend_from_slice(bytes);
+                if mem.len() > self.limit {
+                    let memfd = memfd::MemfdOptions::default()
+                        .allow_sealing(true)
+                        .create("mda-mailchannels-spill")
+                        .map_err(|_| {
+                            MainError::AttachmentIssue("failed to create memfd for spilled buffer")
+                        })?;
+                    let mut file = memfd.as_file().try_clone()?;
+                    file.write_all(mem)?;
+                    self.state = WriterState::Spilled(file, memfd);
+                }
+                Ok(())
+            }
+            WriterState::Spilled(file, _) => Ok(file.write_all(bytes)?),
+        }
+    }
+
+    /// Seals the backing memfd (if any data was spilled) and returns the
+    /// finished, read-positioned [`SpillBuf`].
+    pub(crate) fn finish(self) -> Result<SpillBuf, MainError> {
+        match self.state {
+            WriterState::Memory(mem) => Ok(SpillBuf {
+                len: mem.len(),
+                backing: Backing::Memory(mem),
+            }),
+            WriterState::Spilled(mut file, memfd) => {
+                let len = file.stream_position()? as usize;
+                file.seek(SeekFrom::Start(0))?;
+                memfd
+                    .add_seals(&[
+                        memfd::FileSeal::SealShrink,
+                        memfd::FileSeal::SealGrow,
+                        memfd::FileSeal::SealWrite,
+                        memfd::FileSeal::SealSeal,
+                    ])
+                    .map_err(|_| MainError::AttachmentIssue("failed to seal spilled buffer"))?;
+                Ok(SpillBuf {
+                    len,
+                    backing: Backing::Spilled(file),
+                })
+            }
+        }
+    }
+}
+
+/// A read-only view into a [`SpillBuf`], borrowed for `Memory` backing or
+/// `mmap`-ed for `Spilled` backing. Derefs to `&[u8]` either way.
+pub(crate) enum BufView<'a> {
+    Memory(&'a [u8]),
+    Mapped { ptr: std::ptr::NonNull<u8>, len: usize },
+}
+
+impl std::ops::Deref for BufView<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BufView::Memory(slice) => slice,
+            BufView::Mapped { ptr, len } => unsafe { std::slice::from_raw_parts(ptr.as_ptr(), *len) },
+        }
+    }
+}
+
+impl Drop for BufView<'_> {
+    fn drop(&mut self) {
+        if let BufView::Mapped { ptr, len } = self {
+            unsafe {
+                let _ = munmap(ptr.cast(), *len);
+            }
+        }
+    }
+}